@@ -1,7 +1,7 @@
 use kcov_rs::open;
 
 fn main() {
-    let mut handle = open();
+    let mut handle = open().expect("failed to open kcov");
     let result = handle.collect(|| {
         println!("Hello world!");
     });
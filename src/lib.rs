@@ -1,13 +1,15 @@
+use std::cell::Cell;
+use std::marker::PhantomData;
 use std::mem;
 use std::num::NonZero;
-use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::fd::BorrowedFd;
 use std::os::raw::c_void;
 use std::os::unix::io::RawFd;
 use std::ptr::NonNull;
 
 use nix::errno::Errno;
 use nix::sys::{mman, stat};
-use nix::{Result, fcntl, libc, request_code_none, request_code_read, unistd};
+use nix::{Result, fcntl, libc, request_code_none, request_code_read, request_code_write, unistd};
 
 pub const KCOV: &str = "/sys/kernel/debug/kcov";
 pub const KCOV_BUF_LEN: usize = 1024 * 1024 * 8;
@@ -16,18 +18,83 @@ const KCOV_MAGIC: u8 = b'c';
 const KCOV_INIT_TRACE: u8 = 1;
 const KCOV_ENABLE: u8 = 100;
 const KCOV_DISABLE: u8 = 101;
+const KCOV_REMOTE_ENABLE: u8 = 103;
 
-macro_rules! exits {
-	( $code:expr ) => {
-		::std::process::exit($code)
-	};
+/// Trace the executed PCs (the default kcov mode).
+const KCOV_TRACE_PC: libc::c_ulong = 0;
+/// Trace the operands of comparison instructions instead of PCs.
+const KCOV_TRACE_CMP: libc::c_ulong = 1;
 
-	( $code :expr, $fmt:expr $( , $arg:expr )* ) => {{
-        eprintln!($fmt $( , $arg )*);
-		::std::process::exit($code)
-	}};
+/// Set on a comparison record's `type` word when one operand is a
+/// compile-time constant.
+const KCOV_CMP_CONST: u64 = 1;
+/// Mask for the `log2(operand size in bytes)` bits of a comparison
+/// record's `type` word.
+const KCOV_CMP_SIZE: u64 = 0b110;
+
+/// Selects which kind of data a [`CovHandle`] collects while enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceMode {
+    /// Record every executed program counter (`KCOV_TRACE_PC`).
+    Pc,
+    /// Record the operands of every comparison instruction (`KCOV_TRACE_CMP`).
+    Cmp,
+}
+
+impl TraceMode {
+    fn as_raw(self) -> libc::c_ulong {
+        match self {
+            TraceMode::Pc => KCOV_TRACE_PC,
+            TraceMode::Cmp => KCOV_TRACE_CMP,
+        }
+    }
+}
+
+/// A single decoded `KCOV_TRACE_CMP` record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CmpEntry {
+    /// Whether one of the two operands is a compile-time constant.
+    pub is_const: bool,
+    /// Size of the compared operands, in bytes (1, 2, 4 or 8).
+    pub size_bytes: u8,
+    /// First operand.
+    pub arg1: u64,
+    /// Second operand.
+    pub arg2: u64,
+    /// Program counter of the comparison instruction.
+    pub pc: u64,
+}
+
+/// An error produced while opening, configuring or collecting from a
+/// [`CovHandle`].
+#[derive(Debug)]
+pub enum KcovError {
+    /// Failed to open [`KCOV`].
+    Open(Errno),
+    /// `KCOV_INIT_TRACE` failed.
+    Init(Errno),
+    /// `mmap`-ing the shared trace buffer failed.
+    Mmap(Errno),
+    /// `KCOV_ENABLE` or `KCOV_REMOTE_ENABLE` failed.
+    Enable(Errno),
+    /// `KCOV_DISABLE` failed.
+    Disable(Errno),
+}
+
+impl std::fmt::Display for KcovError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KcovError::Open(e) => write!(f, "failed to open {KCOV}: {e}"),
+            KcovError::Init(e) => write!(f, "failed to init kcov trace: {e}"),
+            KcovError::Mmap(e) => write!(f, "failed to map kcov: {e}"),
+            KcovError::Enable(e) => write!(f, "failed to enable kcov trace: {e}"),
+            KcovError::Disable(e) => write!(f, "failed to disable kcov trace: {e}"),
+        }
+    }
 }
 
+impl std::error::Error for KcovError {}
+
 unsafe fn kcov_init(fd: RawFd, len: usize) -> Result<libc::c_int> {
     let res = unsafe {
         libc::ioctl(
@@ -40,8 +107,14 @@ unsafe fn kcov_init(fd: RawFd, len: usize) -> Result<libc::c_int> {
     Errno::result(res)
 }
 
-unsafe fn kcov_enable(fd: RawFd) -> Result<libc::c_int> {
-    let res = unsafe { libc::ioctl(fd, request_code_none!(KCOV_MAGIC, KCOV_ENABLE), 0) };
+unsafe fn kcov_enable(fd: RawFd, mode: TraceMode) -> Result<libc::c_int> {
+    let res = unsafe {
+        libc::ioctl(
+            fd,
+            request_code_none!(KCOV_MAGIC, KCOV_ENABLE),
+            mode.as_raw(),
+        )
+    };
     Errno::result(res)
 }
 
@@ -50,90 +123,465 @@ unsafe fn kcov_disable(fd: RawFd) -> Result<libc::c_int> {
     Errno::result(res)
 }
 
-pub struct CovHandle {
-    fd: RawFd,
-    pcs: NonNull<c_void>,
-    len: NonNull<c_void>,
-    mem: NonNull<c_void>,
+/// Mirrors the kernel's `struct kcov_remote_arg` header, minus its
+/// trailing `handles[0]` flexible array member.
+#[repr(C)]
+struct KcovRemoteArgHeader {
+    trace_mode: u32,
+    area_size: u32,
+    num_handles: u32,
+    common_handle: u64,
 }
 
-pub fn open() -> CovHandle {
-    let fd = fcntl::open(KCOV, fcntl::OFlag::O_RDWR, stat::Mode::empty())
-        .unwrap_or_else(|e| exits!(exitcode::OSERR, "Fail to open {}: {}", KCOV, e));
+unsafe fn kcov_remote_enable(
+    fd: RawFd,
+    mode: TraceMode,
+    area_size: usize,
+    common_handle: u64,
+    handles: &[u64],
+) -> Result<libc::c_int> {
+    let header_len = mem::size_of::<KcovRemoteArgHeader>();
+    let buf_len = header_len + mem::size_of_val(handles);
+    let mut buf = vec![0u8; buf_len];
 
     unsafe {
-        use mman::MapFlags;
-        use mman::ProtFlags;
-
-        kcov_init(fd, KCOV_BUF_LEN / mem::size_of::<usize>())
-            .unwrap_or_else(|e| exits!(exitcode::OSERR, "Fail to init kcov trace: {}", e));
-
-        let mem = mman::mmap(
-            None,
-            NonZero::new(KCOV_BUF_LEN).unwrap(),
-            ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
-            MapFlags::MAP_SHARED,
-            OwnedFd::from_raw_fd(fd),
-            0,
+        // `buf` is only 1-byte aligned, so an aligned `ptr::write` of the
+        // (8-byte-aligned) header here would be UB; write unaligned instead.
+        buf.as_mut_ptr()
+            .cast::<KcovRemoteArgHeader>()
+            .write_unaligned(KcovRemoteArgHeader {
+                trace_mode: mode.as_raw() as u32,
+                area_size: area_size as u32,
+                num_handles: handles.len() as u32,
+                common_handle,
+            });
+        // Likewise, `handles` is `u64`-aligned but its destination inside
+        // `buf` is not, so each element is written unaligned too.
+        let handles_ptr = buf.as_mut_ptr().add(header_len).cast::<u64>();
+        for (i, &handle) in handles.iter().enumerate() {
+            handles_ptr.add(i).write_unaligned(handle);
+        }
+    }
+
+    let res = unsafe {
+        libc::ioctl(
+            fd,
+            request_code_write!(KCOV_MAGIC, KCOV_REMOTE_ENABLE, header_len),
+            buf.as_ptr(),
         )
-        .unwrap_or_else(|e| exits!(exitcode::OSERR, "Fail to map kcov: {}", e));
+    };
+    Errno::result(res)
+}
 
-        let cover = mem;
-        let len = cover;
-        let pcs = cover.add(1);
-        CovHandle { fd, pcs, len, mem }
-    }
+/// Abstracts the kernel interactions a [`CovHandle`] needs (open, init,
+/// enable, disable and access to the shared trace buffer), so the
+/// buffer-decoding logic in [`CovHandle`] can be exercised without a real
+/// `/sys/kernel/debug/kcov` device. [`NixKcovSource`] is the real
+/// implementation backing [`open`] and [`CovHandle::builder`]; tests provide
+/// their own in-process implementation instead.
+pub trait KcovSource: Sized {
+    /// Opens/allocates the resource and sizes its trace buffer to `area_len`
+    /// `usize` words (mirrors `KCOV_INIT_TRACE`'s argument).
+    fn create(area_len: usize) -> std::result::Result<Self, KcovError>;
+    fn enable(&self, mode: TraceMode) -> std::result::Result<(), KcovError>;
+    fn enable_remote(
+        &self,
+        mode: TraceMode,
+        area_len: usize,
+        common_handle: u64,
+        handles: &[u64],
+    ) -> std::result::Result<(), KcovError>;
+    fn disable(&self) -> std::result::Result<(), KcovError>;
+    /// The whole trace buffer, word 0 being the kernel-maintained entry
+    /// count.
+    fn buffer(&self) -> &[usize];
+    fn buffer_mut(&mut self) -> &mut [usize];
 }
 
-impl CovHandle {
-    pub fn collect<F: FnMut()>(&mut self, mut call: F) -> &[usize] {
-        self.clear();
-        let _g = self.enable();
-        call();
-        self.covers()
-    }
+/// [`KcovSource`] backed by a real `/sys/kernel/debug/kcov` file descriptor
+/// and its `mmap`ed trace buffer.
+pub struct NixKcovSource {
+    fd: RawFd,
+    mem: NonNull<c_void>,
+    buf_bytes: usize,
+}
+
+impl KcovSource for NixKcovSource {
+    fn create(area_len: usize) -> std::result::Result<Self, KcovError> {
+        let fd = fcntl::open(KCOV, fcntl::OFlag::O_RDWR, stat::Mode::empty())
+            .map_err(KcovError::Open)?;
 
-    fn clear(&mut self) {
         unsafe {
-            *(self.len.as_ptr() as *mut usize) = 0;
+            use mman::MapFlags;
+            use mman::ProtFlags;
+
+            if let Err(e) = kcov_init(fd, area_len) {
+                let _ = unistd::close(fd);
+                return Err(KcovError::Init(e));
+            }
+
+            let buf_bytes = area_len * mem::size_of::<usize>();
+            let buf_bytes = match NonZero::new(buf_bytes) {
+                Some(buf_bytes) => buf_bytes,
+                None => {
+                    let _ = unistd::close(fd);
+                    return Err(KcovError::Init(Errno::EINVAL));
+                }
+            };
+            let mem = match mman::mmap(
+                None,
+                buf_bytes,
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_SHARED,
+                BorrowedFd::borrow_raw(fd),
+                0,
+            ) {
+                Ok(mem) => mem,
+                Err(e) => {
+                    let _ = unistd::close(fd);
+                    return Err(KcovError::Mmap(e));
+                }
+            };
+
+            Ok(NixKcovSource {
+                fd,
+                mem,
+                buf_bytes: buf_bytes.get(),
+            })
         }
     }
 
-    fn enable(&self) -> Guard {
+    fn enable(&self, mode: TraceMode) -> std::result::Result<(), KcovError> {
+        unsafe { kcov_enable(self.fd, mode).map_err(KcovError::Enable)? };
+        Ok(())
+    }
+
+    fn enable_remote(
+        &self,
+        mode: TraceMode,
+        area_len: usize,
+        common_handle: u64,
+        handles: &[u64],
+    ) -> std::result::Result<(), KcovError> {
         unsafe {
-            kcov_enable(self.fd)
-                .unwrap_or_else(|e| exits!(exitcode::OSERR, "Fail to enable kcov trace: {}", e));
+            kcov_remote_enable(self.fd, mode, area_len, common_handle, handles)
+                .map_err(KcovError::Enable)?
+        };
+        Ok(())
+    }
+
+    fn disable(&self) -> std::result::Result<(), KcovError> {
+        unsafe { kcov_disable(self.fd).map_err(KcovError::Disable)? };
+        Ok(())
+    }
+
+    fn buffer(&self) -> &[usize] {
+        unsafe {
+            std::slice::from_raw_parts(
+                self.mem.as_ptr() as *const usize,
+                self.buf_bytes / mem::size_of::<usize>(),
+            )
         }
-        Guard { inner: self }
     }
 
-    fn covers(&self) -> &[usize] {
+    fn buffer_mut(&mut self) -> &mut [usize] {
         unsafe {
-            let len = self.len;
-            std::slice::from_raw_parts(self.pcs.as_ptr() as _, len.as_ptr() as _)
+            std::slice::from_raw_parts_mut(
+                self.mem.as_ptr() as *mut usize,
+                self.buf_bytes / mem::size_of::<usize>(),
+            )
         }
     }
 }
 
-impl Drop for CovHandle {
+impl Drop for NixKcovSource {
     fn drop(&mut self) {
         unsafe {
-            mman::munmap(self.mem, KCOV_BUF_LEN)
-                .unwrap_or_else(|e| exits!(exitcode::OSERR, "Fail to munmap kcov: {}", e));
+            if let Err(e) = mman::munmap(self.mem, self.buf_bytes) {
+                eprintln!("failed to unmap kcov: {e}");
+            }
+        }
+        if let Err(e) = unistd::close(self.fd) {
+            eprintln!("failed to close {KCOV}: {e}");
         }
-        unistd::close(self.fd).unwrap_or_else(|e| exits!(exitcode::OSERR, "Fail to close: {}", e));
     }
 }
 
-pub struct Guard<'a> {
-    inner: &'a CovHandle,
+/// [`KcovSource`] backed by an in-process `Vec`, for tests. Never touches a
+/// real file descriptor or `mmap`ing, so its `Drop` has nothing to undo.
+#[cfg(test)]
+struct MockKcovSource {
+    buf: Vec<usize>,
 }
 
-impl<'a> Drop for Guard<'a> {
+#[cfg(test)]
+impl KcovSource for MockKcovSource {
+    fn create(area_len: usize) -> std::result::Result<Self, KcovError> {
+        Ok(MockKcovSource {
+            buf: vec![0usize; area_len],
+        })
+    }
+
+    fn enable(&self, _mode: TraceMode) -> std::result::Result<(), KcovError> {
+        Ok(())
+    }
+
+    fn enable_remote(
+        &self,
+        _mode: TraceMode,
+        _area_len: usize,
+        _common_handle: u64,
+        _handles: &[u64],
+    ) -> std::result::Result<(), KcovError> {
+        Ok(())
+    }
+
+    fn disable(&self) -> std::result::Result<(), KcovError> {
+        Ok(())
+    }
+
+    fn buffer(&self) -> &[usize] {
+        &self.buf
+    }
+
+    fn buffer_mut(&mut self) -> &mut [usize] {
+        &mut self.buf
+    }
+}
+
+pub struct CovHandle<S: KcovSource = NixKcovSource> {
+    source: S,
+    area_len: usize,
+    mode: TraceMode,
+    last_mode: Cell<TraceMode>,
+}
+
+pub fn open() -> std::result::Result<CovHandle, KcovError> {
+    CovHandle::builder().build()
+}
+
+/// Builds a [`CovHandle`] with a non-default trace buffer size and/or
+/// default trace mode.
+///
+/// By default the buffer is [`KCOV_BUF_LEN`] bytes and the default mode is
+/// [`TraceMode::Pc`], matching [`open`].
+pub struct CovHandleBuilder<S: KcovSource = NixKcovSource> {
+    area_len: usize,
+    mode: TraceMode,
+    _source: PhantomData<S>,
+}
+
+impl<S: KcovSource> Default for CovHandleBuilder<S> {
+    fn default() -> Self {
+        CovHandleBuilder {
+            area_len: KCOV_BUF_LEN / mem::size_of::<usize>(),
+            mode: TraceMode::Pc,
+            _source: PhantomData,
+        }
+    }
+}
+
+impl<S: KcovSource> CovHandleBuilder<S> {
+    /// Size of the shared trace buffer, in `usize` units. Passed verbatim to
+    /// `KCOV_INIT_TRACE`; the `mmap`ed region is `area_len * size_of::<usize>()`
+    /// bytes.
+    pub fn area_len(mut self, area_len: usize) -> Self {
+        self.area_len = area_len;
+        self
+    }
+
+    /// Trace mode [`collect`](CovHandle::collect) enables by default.
+    pub fn mode(mut self, mode: TraceMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn build(self) -> std::result::Result<CovHandle<S>, KcovError> {
+        let source = S::create(self.area_len)?;
+        Ok(CovHandle {
+            source,
+            area_len: self.area_len,
+            mode: self.mode,
+            last_mode: Cell::new(self.mode),
+        })
+    }
+}
+
+impl<S: KcovSource> CovHandle<S> {
+    /// Starts building a [`CovHandle`] with a configurable buffer size and
+    /// default trace mode. Use [`open`] if the defaults suit you.
+    pub fn builder() -> CovHandleBuilder<S> {
+        CovHandleBuilder::default()
+    }
+
+    /// Enables this handle's configured [`TraceMode`] (see
+    /// [`CovHandleBuilder::mode`]), runs `call`, and returns the raw trace
+    /// words the kernel wrote. Use [`collect_cmp`](Self::collect_cmp) to
+    /// decode `KCOV_TRACE_CMP` records instead.
+    pub fn collect<F: FnMut()>(&mut self, mut call: F) -> std::result::Result<&[usize], KcovError> {
+        self.clear();
+        self.last_mode.set(self.mode);
+        let _g = self.enable(self.mode)?;
+        call();
+        let buf = self.source.buffer();
+        let words = match self.mode {
+            TraceMode::Pc => buf[0],
+            TraceMode::Cmp => buf[0] * 4,
+        };
+        Ok(&buf[1..1 + words])
+    }
+
+    /// Like [`collect`](Self::collect), but always enables `KCOV_TRACE_CMP`
+    /// and decodes the comparison records the kernel wrote into the shared
+    /// buffer instead of raw PCs.
+    pub fn collect_cmp<F: FnMut()>(
+        &mut self,
+        mut call: F,
+    ) -> std::result::Result<Vec<CmpEntry>, KcovError> {
+        self.clear();
+        self.last_mode.set(TraceMode::Cmp);
+        let _g = self.enable(TraceMode::Cmp)?;
+        call();
+        Ok(self.cmp_entries())
+    }
+
+    /// Whether the previous `collect`/`collect_cmp` call filled the shared
+    /// buffer to capacity, meaning the kernel stopped recording and some
+    /// coverage was silently dropped.
+    pub fn saturated(&self) -> bool {
+        let count = self.source.buffer()[0];
+        let capacity = match self.last_mode.get() {
+            TraceMode::Pc => self.area_len - 1,
+            TraceMode::Cmp => (self.area_len - 1) / 4,
+        };
+        count >= capacity
+    }
+
+    fn clear(&mut self) {
+        self.source.buffer_mut()[0] = 0;
+    }
+
+    fn enable(&self, mode: TraceMode) -> std::result::Result<Guard<'_, S>, KcovError> {
+        self.source.enable(mode)?;
+        Ok(Guard { inner: self })
+    }
+
+    /// Collect coverage produced by kernel background threads (e.g. USB or
+    /// network softirqs) servicing this task, via `KCOV_REMOTE_ENABLE`. The
+    /// task's own synchronous coverage is not traced by this call.
+    ///
+    /// `common_handle` tags coverage for background work bound to the
+    /// calling task; `handles` additionally tags coverage for independently
+    /// running kernel threads that were told to use those handles. Disable
+    /// by dropping the returned [`Guard`], same as [`collect`](Self::collect).
+    pub fn enable_remote(
+        &self,
+        common_handle: u64,
+        handles: &[u64],
+        mode: TraceMode,
+    ) -> std::result::Result<Guard<'_, S>, KcovError> {
+        self.source
+            .enable_remote(mode, self.area_len, common_handle, handles)?;
+        self.last_mode.set(mode);
+        Ok(Guard { inner: self })
+    }
+
+    /// The raw PC trace words currently sitting in the shared buffer.
+    ///
+    /// `collect` already returns this after running its closure; this is the
+    /// way to read coverage collected via [`enable_remote`](Self::enable_remote),
+    /// where there is no closure to run coverage-producing code.
+    pub fn covers(&self) -> &[usize] {
+        let buf = self.source.buffer();
+        &buf[1..1 + buf[0]]
+    }
+
+    /// The decoded `KCOV_TRACE_CMP` records currently sitting in the shared
+    /// buffer. See [`covers`](Self::covers) for why this is useful alongside
+    /// [`enable_remote`](Self::enable_remote).
+    pub fn cmp_entries(&self) -> Vec<CmpEntry> {
+        let buf = self.source.buffer();
+        let count = buf[0];
+        (0..count)
+            .map(|i| {
+                let base = 1 + i * 4;
+                let ty = buf[base] as u64;
+                CmpEntry {
+                    is_const: ty & KCOV_CMP_CONST != 0,
+                    size_bytes: 1u8 << ((ty & KCOV_CMP_SIZE) >> 1),
+                    arg1: buf[base + 1] as u64,
+                    arg2: buf[base + 2] as u64,
+                    pc: buf[base + 3] as u64,
+                }
+            })
+            .collect()
+    }
+}
+
+pub struct Guard<'a, S: KcovSource = NixKcovSource> {
+    inner: &'a CovHandle<S>,
+}
+
+impl<'a, S: KcovSource> Drop for Guard<'a, S> {
     fn drop(&mut self) {
-        unsafe {
-            kcov_disable(self.inner.fd)
-                .unwrap_or_else(|e| exits!(exitcode::OSERR, "Fail to disable kcov trace: {}", e));
+        if let Err(e) = self.inner.source.disable() {
+            eprintln!("{e}");
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handle_with(words: &[usize]) -> CovHandle<MockKcovSource> {
+        let mut handle = CovHandle::<MockKcovSource>::builder()
+            .area_len(words.len())
+            .build()
+            .unwrap();
+        handle.source.buffer_mut().copy_from_slice(words);
+        handle
+    }
+
+    #[test]
+    fn covers_decodes_pc_trace() {
+        let handle = handle_with(&[2, 0x1000, 0x2000, 0, 0]);
+        assert_eq!(handle.covers(), &[0x1000, 0x2000]);
+    }
+
+    #[test]
+    fn cmp_entries_decodes_records() {
+        // const, 8-byte operands: bit0 set, size bits (log2(8)=3) in bits 1-2.
+        let ty = KCOV_CMP_CONST | (3 << 1);
+        let handle = handle_with(&[1, ty as usize, 10, 20, 0xdead]);
+        let entries = handle.cmp_entries();
+
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].is_const);
+        assert_eq!(entries[0].size_bytes, 8);
+        assert_eq!(entries[0].arg1, 10);
+        assert_eq!(entries[0].arg2, 20);
+        assert_eq!(entries[0].pc, 0xdead);
+    }
+
+    #[test]
+    fn clear_resets_the_entry_count() {
+        let mut handle = handle_with(&[5, 1, 2, 3, 4, 5]);
+        handle.clear();
+        assert_eq!(handle.source.buffer()[0], 0);
+    }
+
+    #[test]
+    fn saturated_when_entry_count_reaches_capacity() {
+        let handle = handle_with(&[2, 0, 0]);
+        handle.last_mode.set(TraceMode::Pc);
+        assert!(handle.saturated());
+    }
+
+    #[test]
+    fn not_saturated_below_capacity() {
+        let handle = handle_with(&[1, 0, 0]);
+        handle.last_mode.set(TraceMode::Pc);
+        assert!(!handle.saturated());
+    }
+}